@@ -2,11 +2,132 @@
 /// Implementation of the callbacks that the CSS selector engine uses to query the DOM.
 ///
 
-use dom::node::AbstractNode;
+use dom::node::{AbstractNode, Quirks, QuirksMode};
 use newcss::select::SelectHandler;
 
+/// Number of buckets in the ancestor Bloom filter. Chosen as a power of two so the
+/// hash reduction is a cheap mask; large enough that the descendant-combinator fast
+/// path stays accurate on realistic documents.
+const BLOOM_SIZE: uint = 4096;
+
+/// A fixed-size counting Bloom filter over the tag names (and, later, ids and classes)
+/// of the current node's ancestors. Counters rather than bits so that a name can be
+/// removed again when the selector traversal ascends back out of a subtree, keeping the
+/// filter holding *exactly* the ancestor chain of the node being matched and never its
+/// cousins.
+pub struct BloomFilter {
+    counters: [u8, ..BLOOM_SIZE]
+}
+
+/// Two FNV variants plus a djb2 give us three independent hash functions over the
+/// ASCII-lowercased name. Lowercasing happens inline to avoid an allocation per probe.
+fn ancestor_hashes(name: &str) -> (uint, uint, uint) {
+    let mut fnv1a: u32 = 0x811c9dc5;
+    let mut fnv1:  u32 = 0x811c9dc5;
+    let mut djb2:  u32 = 5381;
+    for str::each(name) |b| {
+        let c = (if b >= ('A' as u8) && b <= ('Z' as u8) { b + 32 } else { b }) as u32;
+        fnv1a = (fnv1a ^ c) * 0x01000193;
+        fnv1  = (fnv1 * 0x01000193) ^ c;
+        djb2  = (djb2 << 5) + djb2 + c;
+    }
+    (fnv1a as uint % BLOOM_SIZE, fnv1 as uint % BLOOM_SIZE, djb2 as uint % BLOOM_SIZE)
+}
+
+impl BloomFilter {
+    static pub fn new() -> BloomFilter {
+        BloomFilter { counters: [0u8, ..BLOOM_SIZE] }
+    }
+
+    /// Record that `name` appears on an ancestor. Counters saturate at 255; a pathological
+    /// document that overflows one just makes the filter slightly less precise, never wrong.
+    pub fn insert(&mut self, name: &str) {
+        let (a, b, c) = ancestor_hashes(name);
+        if self.counters[a] < 255 { self.counters[a] += 1; }
+        if self.counters[b] < 255 { self.counters[b] += 1; }
+        if self.counters[c] < 255 { self.counters[c] += 1; }
+    }
+
+    /// Undo a previous `insert` as the traversal ascends out of a subtree. A saturated
+    /// counter has lost its exact count, so it is left at 255 rather than decremented.
+    pub fn remove(&mut self, name: &str) {
+        let (a, b, c) = ancestor_hashes(name);
+        if self.counters[a] > 0 && self.counters[a] < 255 { self.counters[a] -= 1; }
+        if self.counters[b] > 0 && self.counters[b] < 255 { self.counters[b] -= 1; }
+        if self.counters[c] > 0 && self.counters[c] < 255 { self.counters[c] -= 1; }
+    }
+
+    /// If any of `name`'s buckets is empty the name provably has no matching ancestor.
+    /// A "maybe" answer still needs the linear walk to rule out false positives.
+    pure fn might_contain(&self, name: &str) -> bool {
+        let (a, b, c) = ancestor_hashes(name);
+        self.counters[a] != 0 && self.counters[b] != 0 && self.counters[c] != 0
+    }
+}
+
 pub struct NodeSelectHandler {
-    node: AbstractNode
+    node: AbstractNode,
+    /// The ancestor Bloom filter maintained by the style traversal, when one is active.
+    /// The CSS engine threads the same filter through every handler it builds during a
+    /// single `initialize_style_for_subtree` pass.
+    ancestors: Option<@mut BloomFilter>,
+    /// The document's quirks mode, as computed from its doctype by `AbstractNode::quirks_mode`.
+    /// Governs whether class and id comparisons are case-sensitive.
+    quirks_mode: QuirksMode
+}
+
+impl NodeSelectHandler {
+    static pub fn new(node: AbstractNode, quirks_mode: QuirksMode) -> NodeSelectHandler {
+        NodeSelectHandler { node: node, ancestors: None, quirks_mode: quirks_mode }
+    }
+
+    static pub fn with_bloom(node: AbstractNode,
+                             ancestors: @mut BloomFilter,
+                             quirks_mode: QuirksMode) -> NodeSelectHandler {
+        NodeSelectHandler { node: node, ancestors: Some(ancestors), quirks_mode: quirks_mode }
+    }
+
+    /// The quirks mode this handler matches under, for engines that adjust their
+    /// class-attribute parsing accordingly.
+    pub fn quirks_mode(&self) -> QuirksMode {
+        self.quirks_mode
+    }
+}
+
+/// Compares two names, case-insensitively (ASCII) when in quirks mode and exactly
+/// otherwise. Used for id and class selector matching. The quirks comparison only folds
+/// ASCII letters and leaves other bytes (including UTF-8 sequences) untouched, so it never
+/// fails on valid non-ASCII input and allocates nothing.
+fn names_match(mode: QuirksMode, a: &str, b: &str) -> bool {
+    match mode {
+        Quirks => eq_ignore_ascii_case(a, b),
+        _ => a == b,
+    }
+}
+
+fn eq_ignore_ascii_case(a: &str, b: &str) -> bool {
+    do str::byte_slice(a) |a_bytes| {
+        do str::byte_slice(b) |b_bytes| {
+            if a_bytes.len() != b_bytes.len() {
+                false
+            } else {
+                let mut i = 0;
+                let mut equal = true;
+                while i < a_bytes.len() {
+                    if ascii_lower(a_bytes[i]) != ascii_lower(b_bytes[i]) {
+                        equal = false;
+                        break;
+                    }
+                    i += 1;
+                }
+                equal
+            }
+        }
+    }
+}
+
+fn ascii_lower(b: u8) -> u8 {
+    if b >= ('A' as u8) && b <= ('Z' as u8) { b + 32 } else { b }
 }
 
 fn with_node_name<R>(node: AbstractNode, f: &fn(&str) -> R) -> R {
@@ -16,6 +137,29 @@ fn with_node_name<R>(node: AbstractNode, f: &fn(&str) -> R) -> R {
     f(node.as_element().tag_name)
 }
 
+/// A bitset over the boolean-ish attributes that turn up most often in selectors. Unlike
+/// the earlier version that re-derived the bits from `get_attr` on every probe (and so
+/// saved nothing), this is the value precomputed and cached on `Element` whenever its
+/// attributes change, and exposed through `Element::style_affecting_attributes`. Matching
+/// `[disabled]`, `[checked]`, and `[type]` then costs a single bit test.
+pub struct CommonStyleAffectingAttributes {
+    bits: u8
+}
+
+pub const DISABLED: u8 = 0x01;
+pub const CHECKED:  u8 = 0x02;
+pub const HAS_TYPE: u8 = 0x04;
+
+impl CommonStyleAffectingAttributes {
+    static pub fn new(bits: u8) -> CommonStyleAffectingAttributes {
+        CommonStyleAffectingAttributes { bits: bits }
+    }
+
+    pure fn has(&self, flag: u8) -> bool {
+        (self.bits & flag) != 0
+    }
+}
+
 impl SelectHandler<AbstractNode> for NodeSelectHandler {
     fn with_node_name<R>(node: &AbstractNode, f: &fn(&str) -> R) -> R {
         with_node_name(*node, f)
@@ -40,8 +184,15 @@ impl SelectHandler<AbstractNode> for NodeSelectHandler {
         node.parent()
     }
 
-    // TODO: Use a Bloom filter.
     fn named_ancestor_node(node: &AbstractNode, name: &str) -> Option<AbstractNode> {
+        // Fast path: if the Bloom filter (holding exactly this node's ancestors) says the
+        // name is absent, there is provably no matching ancestor and we can skip the walk.
+        match self.ancestors {
+            Some(bloom) if !bloom.might_contain(name) => return None,
+            _ => {}
+        }
+
+        // The filter only answers "maybe"; walk the chain to rule out false positives.
         let mut node = *node;
         loop {
             let parent = node.parent();
@@ -80,7 +231,106 @@ impl SelectHandler<AbstractNode> for NodeSelectHandler {
         }
         match node.as_element().get_attr("id") {
             None => false,
-            Some(existing_id) => id == existing_id
+            Some(existing_id) => names_match(self.quirks_mode, id, existing_id)
+        }
+    }
+
+    /// Exposes the element's whitespace-separated `class` attribute to the engine so it
+    /// can tokenize and match class selectors. Mirrors `with_node_id`.
+    fn with_node_classes<R>(node: &AbstractNode, f: &fn(Option<&str>) -> R) -> R {
+        if !node.is_element() {
+            fail!(~"attempting to style non-element node");
+        }
+        f(node.as_element().get_attr("class"))
+    }
+
+    fn node_has_class(node: &AbstractNode, name: &str) -> bool {
+        if !node.is_element() {
+            fail!(~"attempting to style non-element node");
+        }
+        match node.as_element().get_attr("class") {
+            None => false,
+            Some(class_attr) => str::words(class_attr).any(|token| names_match(self.quirks_mode, *token, name))
+        }
+    }
+
+    /// Generic attribute accessor backing `[attr]` (presence) and `[attr=val]` (exact
+    /// value) matching. Presence of a common style-affecting attribute is answered from
+    /// the bitset precomputed on `Element`, avoiding a string lookup; everything else
+    /// (and every value comparison) falls through to `Element::get_attr`.
+    fn node_has_attribute(node: &AbstractNode, name: &str, value: Option<&str>) -> bool {
+        if !node.is_element() {
+            fail!(~"attempting to style non-element node");
+        }
+
+        let element = node.as_element();
+        match value {
+            None => {
+                let common = element.style_affecting_attributes();
+                match name {
+                    "disabled" => common.has(DISABLED),
+                    "checked"  => common.has(CHECKED),
+                    "type"     => common.has(HAS_TYPE),
+                    _ => element.get_attr(name).is_some(),
+                }
+            }
+            Some(value) => {
+                match element.get_attr(name) {
+                    None => false,
+                    Some(existing) => existing == value
+                }
+            }
+        }
+    }
+
+    /// The immediately preceding element sibling matching `name`, for the adjacent-sibling
+    /// combinator `a + b`. Only the nearest preceding element is considered (intervening
+    /// text nodes are skipped), so `a + b` never matches a non-adjacent `a`. Mirrors the
+    /// immediate `named_parent_node`.
+    fn named_prev_sibling(node: &AbstractNode, name: &str) -> Option<AbstractNode> {
+        let mut prev = node.prev_sibling();
+        loop {
+            match prev {
+                Some(sibling) => {
+                    if sibling.is_element() {
+                        return do with_node_name(sibling) |node_name| {
+                            if name == node_name {
+                                Some(sibling)
+                            } else {
+                                None
+                            }
+                        };
+                    }
+                    prev = sibling.prev_sibling();
+                }
+                None => return None
+            }
+        }
+    }
+
+    /// Walks the whole preceding-sibling chain for the general-sibling combinator `a ~ b`,
+    /// the way `named_ancestor_node` walks the parent chain. For adjacent `a + b` the
+    /// engine must use `named_prev_sibling` instead.
+    fn named_preceding_sibling(node: &AbstractNode, name: &str) -> Option<AbstractNode> {
+        let mut node = *node;
+        loop {
+            match node.prev_sibling() {
+                Some(sibling) => {
+                    if sibling.is_element() {
+                        let mut found = false;
+                        do with_node_name(sibling) |node_name| {
+                            if name == node_name {
+                                found = true;
+                            }
+                        }
+                        if found {
+                            return Some(sibling);
+                        }
+                    }
+                    node = sibling;
+                }
+                None => return None
+            }
         }
     }
 }
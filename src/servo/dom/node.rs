@@ -7,9 +7,11 @@ use dom::document::Document;
 use dom::element::{Element, ElementTypeId, HTMLImageElement, HTMLImageElementTypeId};
 use dom::element::{HTMLStyleElementTypeId};
 use dom::window::Window;
+use css::select_handler::NodeSelectHandler;
 use layout::debug::DebugMethods;
 use layout::flow::FlowContext;
 use newcss::complete::CompleteSelectResults;
+use newcss::selector::{Selector, parse_selector_list};
 
 use core::cast::transmute;
 use core::ptr::null;
@@ -45,7 +47,7 @@ pub struct Node {
     prev_sibling: Option<AbstractNode>,
 
     // You must not touch this if you are not layout.
-    priv layout_data: Option<@LayoutData>
+    priv layout_data: Option<@mut LayoutData>
 }
 
 #[deriving_eq]
@@ -72,12 +74,36 @@ impl LayoutData {
             flow: None,
         }
     }
+
+    /// Adopts another node's computed style instead of recomputing our own. Used by the
+    /// sibling style-sharing cache when a neighbour is known to match identical rules.
+    /// Only the style is shared; flow is left untouched since it is rebuilt per node.
+    pub fn share_style_from(&mut self, other: &LayoutData) {
+        self.style = copy other.style;
+    }
+
+    /// Discards cached style and flow so the next restyle recomputes them. Called after a
+    /// tree mutation leaves the previously computed layout stale.
+    pub fn invalidate(&mut self) {
+        self.style = None;
+        self.flow = None;
+    }
 }
 
 //
 // Basic node types
 //
 
+/// The document-wide rendering mode derived from the doctype. Quirks mode loosens a
+/// number of comparisons (notably making class and id selector matching
+/// ASCII-case-insensitive); limited-quirks differs only in a handful of layout details.
+#[deriving_eq]
+pub enum QuirksMode {
+    NoQuirks,
+    LimitedQuirks,
+    Quirks,
+}
+
 pub struct Doctype {
     parent: Node,
     name: ~str,
@@ -100,6 +126,26 @@ impl Doctype {
             force_quirks: force_quirks,
         }
     }
+
+    /// The quirks mode this doctype selects. A forced-quirks flag wins outright;
+    /// otherwise a few recognized legacy public ids fall into limited-quirks mode and
+    /// everything else is no-quirks. A document with no doctype at all is treated as
+    /// full quirks by the caller, since there is no `Doctype` to consult.
+    pub fn quirks_mode(&self) -> QuirksMode {
+        if self.force_quirks {
+            return Quirks;
+        }
+        match self.public_id {
+            Some(ref public_id) if is_limited_quirks_public_id(*public_id) => LimitedQuirks,
+            _ => NoQuirks,
+        }
+    }
+}
+
+/// The legacy public ids that trigger limited-quirks (rather than full quirks) mode.
+fn is_limited_quirks_public_id(public_id: &str) -> bool {
+    public_id.starts_with("-//W3C//DTD XHTML 1.0 Frameset//") ||
+        public_id.starts_with("-//W3C//DTD XHTML 1.0 Transitional//")
 }
 
 pub struct Comment {
@@ -178,6 +224,93 @@ impl AbstractNode {
         }
 
         child_n.prev_sibling = parent_n.last_child;
+
+        // Note: no layout invalidation here. The cached layout of `self` and its ancestors
+        // is left as it is, even when they were already styled; tree building is
+        // append-heavy and a per-append subtree + ancestor walk would be O(n^2).
+    }
+
+    // Invariant: `new_child` is disconnected and `reference_child` is a child of `self`.
+    fn insert_before(self, new_child: AbstractNode, reference_child: AbstractNode) {
+        assert self != new_child;
+
+        let new_child_n = new_child.as_node();
+        let reference_n = reference_child.as_node();
+
+        assert new_child_n.parent.is_none();
+        assert new_child_n.prev_sibling.is_none();
+        assert new_child_n.next_sibling.is_none();
+        assert reference_n.parent == Some(self);
+
+        new_child_n.parent = Some(self);
+        new_child_n.next_sibling = Some(reference_child);
+        new_child_n.prev_sibling = reference_n.prev_sibling;
+
+        match reference_n.prev_sibling {
+            None => self.as_node().first_child = Some(new_child),
+            Some(prev) => prev.as_node().next_sibling = Some(new_child),
+        }
+        reference_n.prev_sibling = Some(new_child);
+
+        self.invalidate_layout();
+    }
+
+    // Invariant: `child` is a child of `self`.
+    fn remove_child(self, child: AbstractNode) {
+        let parent_n = self.as_node();
+        let child_n = child.as_node();
+
+        assert child_n.parent == Some(self);
+
+        match child_n.prev_sibling {
+            None => parent_n.first_child = child_n.next_sibling,
+            Some(prev) => prev.as_node().next_sibling = child_n.next_sibling,
+        }
+        match child_n.next_sibling {
+            None => parent_n.last_child = child_n.prev_sibling,
+            Some(next) => next.as_node().prev_sibling = child_n.prev_sibling,
+        }
+
+        child_n.parent = None;
+        child_n.prev_sibling = None;
+        child_n.next_sibling = None;
+
+        self.invalidate_layout();
+        child.invalidate_layout();
+    }
+
+    // Invariant: `old_child` is a child of `self` and `new_child` is disconnected.
+    fn replace_child(self, new_child: AbstractNode, old_child: AbstractNode) {
+        assert old_child.as_node().parent == Some(self);
+
+        self.insert_before(new_child, old_child);
+        self.remove_child(old_child);
+    }
+
+    /// Marks cached layout stale after a mutation: the touched subtree and every ancestor
+    /// up to the root have their `LayoutData` style and flow cleared, so none of them keeps
+    /// a dangling flow tree. A subsequent `initialize_style_for_subtree` restyles every
+    /// element it visits, whether or not its layout data was cleared here.
+    fn invalidate_layout(self) {
+        do self.traverse_preorder |node| {
+            do node.as_node().layout_data.map |data| {
+                data.invalidate();
+            };
+            true
+        };
+
+        let mut ancestor = self.parent();
+        loop {
+            match ancestor {
+                Some(node) => {
+                    do node.as_node().layout_data.map |data| {
+                        data.invalidate();
+                    };
+                    ancestor = node.parent();
+                }
+                None => break
+            }
+        }
     }
 
     //
@@ -233,6 +366,17 @@ impl AbstractNode {
         }
     }
 
+    fn is_doctype(self) -> bool { self.as_node().type_id == DoctypeNodeTypeId }
+
+    fn as_doctype(&self) -> &self/mut Doctype {
+        if !self.is_doctype() {
+            fail!(~"node is not a doctype");
+        }
+        unsafe {
+            transmute(self.obj)
+        }
+    }
+
     fn is_element(self) -> bool {
         match self.as_node().type_id {
             ElementNodeTypeId(*) => true,
@@ -265,6 +409,90 @@ impl AbstractNode {
     fn is_style_element(self) -> bool {
         self.as_node().type_id == ElementNodeTypeId(HTMLStyleElementTypeId)
     }
+
+    /// The quirks mode of the document this node belongs to, derived from its doctype.
+    /// Ascends to the topmost node and inspects it and its immediate children for a
+    /// doctype; an absent doctype is treated as full quirks per the HTML parsing spec.
+    fn quirks_mode(self) -> QuirksMode {
+        let mut root = self;
+        loop {
+            match root.parent() {
+                Some(parent) => root = parent,
+                None => break
+            }
+        }
+
+        let mut doctype = None;
+        if root.is_doctype() {
+            doctype = Some(root);
+        } else {
+            for root.each_child |kid| {
+                if kid.is_doctype() {
+                    doctype = Some(kid);
+                    break;
+                }
+            }
+        }
+
+        match doctype {
+            Some(node) => node.as_doctype().quirks_mode(),
+            None => Quirks,
+        }
+    }
+
+    //
+    // DOM selection API
+    //
+    // Parses the selector string once through `newcss` and then evaluates it against the
+    // subtree rooted at this node using the same `NodeSelectHandler` callbacks the layout
+    // restyle pass uses, so the query surface stays in lockstep with what the CSS engine
+    // can actually match.
+    //
+
+    /// Returns the first element in this subtree (in preorder) that matches `selector`,
+    /// or `None` if nothing matches or the selector string fails to parse.
+    fn query_selector(self, selector: &str) -> Option<AbstractNode> {
+        let selectors = match parse_selector_list(selector) {
+            Ok(selectors) => selectors,
+            Err(_) => return None,
+        };
+        let quirks_mode = self.quirks_mode();
+        let mut result = None;
+        do self.traverse_preorder |node| {
+            if node.is_element() && matches_any(node, selectors, quirks_mode) {
+                result = Some(node);
+                false
+            } else {
+                true
+            }
+        };
+        result
+    }
+
+    /// Returns every element in this subtree (in preorder) that matches `selector`, or an
+    /// empty vector if nothing matches or the selector string fails to parse.
+    fn query_selector_all(self, selector: &str) -> ~[AbstractNode] {
+        let selectors = match parse_selector_list(selector) {
+            Ok(selectors) => selectors,
+            Err(_) => return ~[],
+        };
+        let quirks_mode = self.quirks_mode();
+        let mut results = ~[];
+        do self.traverse_preorder |node| {
+            if node.is_element() && matches_any(node, selectors, quirks_mode) {
+                results.push(node);
+            }
+            true
+        };
+        results
+    }
+}
+
+/// Tests a single element against a parsed selector list, matching under the document's
+/// quirks mode so class and id comparisons behave consistently with the restyle pass.
+fn matches_any(node: AbstractNode, selectors: &[Selector], quirks_mode: QuirksMode) -> bool {
+    let handler = NodeSelectHandler::new(node, quirks_mode);
+    selectors.any(|selector| selector.matches(&node, &handler))
 }
 
 impl Node {
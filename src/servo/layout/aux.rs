@@ -2,37 +2,208 @@
 Code for managing the DOM aux pointer
 */
 
-use dom::node::{AbstractNode, LayoutData};
+use css::select_handler::{BloomFilter, NodeSelectHandler};
+use dom::node::{AbstractNode, LayoutData, QuirksMode};
 use core::dvec::DVec;
+use newcss::select::SelectCtx;
+
+/// How many recently styled siblings we keep around as style-sharing candidates. Small
+/// because sharing is only worthwhile between near neighbours (think consecutive table
+/// rows or list items); a longer list would cost more to probe than it saves.
+const STYLE_SHARING_CANDIDATES: uint = 8;
+
+/// The characteristics of a previously styled node that fully determine its style under
+/// the selectors we currently support, together with a handle to that node so its
+/// `LayoutData::style` can be shared rather than recomputed.
+pub struct StyleSharingCandidate {
+    node: AbstractNode,
+    parent: Option<AbstractNode>,
+    tag_name: ~str,
+    classes: ~[~str],
+}
+
+impl StyleSharingCandidate {
+    /// A node is a valid sharing source only when its style is fully determined by the
+    /// characteristics we compare below. It is rejected outright if it carries an `id`
+    /// (potentially unique style) or any common style-affecting attribute such as
+    /// `type`/`disabled`/`checked`, since those change matched rules without changing the
+    /// tag or class list we key on.
+    static pub fn new(node: AbstractNode) -> Option<StyleSharingCandidate> {
+        let element = node.as_element();
+        if element.get_attr("id").is_some() || has_common_style_affecting_attributes(node) {
+            return None;
+        }
+        Some(StyleSharingCandidate {
+            node: node,
+            parent: node.parent(),
+            tag_name: element.tag_name.to_owned(),
+            classes: element_classes(node),
+        })
+    }
+
+    /// Two siblings share style when they have the same parent, the same tag name, no id
+    /// and no common style-affecting attributes on either side (the candidate is already
+    /// free of both), and identical class lists.
+    fn can_share_with(&self, node: AbstractNode) -> bool {
+        let element = node.as_element();
+        self.parent == node.parent() &&
+            element.get_attr("id").is_none() &&
+            !has_common_style_affecting_attributes(node) &&
+            self.tag_name == element.tag_name &&
+            self.classes == element_classes(node)
+    }
+}
+
+/// An LRU of recent style-sharing candidates, consulted before the CSS engine runs.
+pub struct StyleSharingCache {
+    candidates: ~[StyleSharingCandidate],
+    /// Whether the active stylesheet contains sibling- or position-sensitive selectors
+    /// (`+`, `~`, `:first-child`, ...). When it does, sharing between siblings is unsound
+    /// because two otherwise-identical elements can still match different rules.
+    sibling_affecting: bool,
+    /// Whether the active stylesheet contains attribute selectors (`[attr]`, `[attr=v]`).
+    /// `can_share_with` only compares id/class and the common boolean-ish attributes, so
+    /// it cannot tell apart siblings that differ in some arbitrary attribute (`data-x`,
+    /// `lang`, `href`, ...). A rule like `[data-x=1]{}` would then be shared incorrectly,
+    /// so the presence of any attribute selector disables sharing too.
+    attribute_affecting: bool,
+}
+
+impl StyleSharingCache {
+    static pub fn new(sibling_affecting: bool, attribute_affecting: bool) -> StyleSharingCache {
+        StyleSharingCache {
+            candidates: ~[],
+            sibling_affecting: sibling_affecting,
+            attribute_affecting: attribute_affecting,
+        }
+    }
+
+    /// If some cached candidate can share its style with `node`, copy that style onto
+    /// `node`'s layout data and return true, letting the caller skip selector matching.
+    fn share_style_for(&self, node: AbstractNode) -> bool {
+        if self.sibling_affecting || self.attribute_affecting {
+            return false;
+        }
+        for self.candidates.each |candidate| {
+            if candidate.can_share_with(node) {
+                node.as_node().layout_data.map(|dst| {
+                    candidate.node.as_node().layout_data.map(|src| {
+                        dst.share_style_from(*src);
+                    });
+                });
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Record a freshly styled node as the most-recent candidate, evicting the oldest
+    /// once we exceed the cache size. Nodes carrying an id are silently not cached.
+    fn add(&mut self, node: AbstractNode) {
+        match StyleSharingCandidate::new(node) {
+            None => {}
+            Some(candidate) => {
+                self.candidates.unshift(candidate);
+                if self.candidates.len() > STYLE_SHARING_CANDIDATES {
+                    self.candidates.pop();
+                }
+            }
+        }
+    }
+}
+
+/// Tokenizes an element's `class` attribute into individual class names.
+fn element_classes(node: AbstractNode) -> ~[~str] {
+    match node.as_element().get_attr("class") {
+        None => ~[],
+        Some(class_attr) => str::words(class_attr),
+    }
+}
+
+/// Whether the element carries any of the boolean-ish attributes that commonly affect
+/// matched rules (`type`, `disabled`, `checked`). Such elements are never shared, so that
+/// e.g. `<input type=text>` and `<input type=checkbox>` do not receive the same style.
+fn has_common_style_affecting_attributes(node: AbstractNode) -> bool {
+    let element = node.as_element();
+    element.get_attr("disabled").is_some() ||
+        element.get_attr("checked").is_some() ||
+        element.get_attr("type").is_some()
+}
 
 pub trait LayoutAuxMethods {
-    fn initialize_layout_data(self) -> Option<@LayoutData>;
-    fn initialize_style_for_subtree(self, refs: &DVec<@LayoutData>);
+    fn initialize_layout_data(self) -> Option<@mut LayoutData>;
+    fn initialize_style_for_subtree(self, ctx: &SelectCtx, refs: &DVec<@mut LayoutData>);
 }
 
 impl AbstractNode : LayoutAuxMethods {
     /// If none exists, creates empty layout data for the node (the reader-auxiliary
-    /// box in the COW model) and populates it with an empty style object.
-    fn initialize_layout_data(self) -> Option<@LayoutData> {
+    /// box in the COW model) and populates it with an empty style object. The box is
+    /// `@mut` so that layout can later invalidate or share its contents.
+    fn initialize_layout_data(self) -> Option<@mut LayoutData> {
         let node = self.as_node();
         match node.layout_data {
             Some(_) => None,
             None => {
-                let data = Some(LayoutData::new());
-                node.data = data;
+                let data = Some(@mut LayoutData::new());
+                node.layout_data = data;
                 data
             }
         }
     }
 
-    /// Initializes layout data and styles for a Node tree, if any nodes do not have
-    /// this data already. Append created layout data to the task's GC roots.
-    fn initialize_style_for_subtree(self, refs: &DVec<@LayoutData>) {
-        do self.traverse_preorder |n| {
-            match n.initialize_layout_data() {
-                Some(r) => refs.push(r),
-                None => {}
+    /// Initializes layout data and computes styles for a Node tree, using `ctx` as the
+    /// selector-matching context. Appends any created layout data to the task's GC roots.
+    ///
+    /// Drives the recursion by hand rather than through `traverse_preorder` so that an
+    /// ancestor Bloom filter can be threaded alongside it: each element's tag name is
+    /// inserted before descending into its children and removed on the way back up, so
+    /// the filter always holds exactly the ancestors of the node currently being styled.
+    /// The `NodeSelectHandler` that drives each `select_style` call is built with
+    /// `with_bloom`, so `named_ancestor_node` can use the filter to short-circuit
+    /// descendant and ancestor combinator matching.
+    fn initialize_style_for_subtree(self, ctx: &SelectCtx, refs: &DVec<@mut LayoutData>) {
+        let bloom = @mut BloomFilter::new();
+        let sharing = @mut StyleSharingCache::new(ctx.sibling_affecting_selectors(),
+                                                  ctx.attribute_affecting_selectors());
+        let quirks_mode = self.quirks_mode();
+        self.initialize_style_for_subtree_with_bloom(ctx, quirks_mode, refs, bloom, sharing);
+    }
+
+    priv fn initialize_style_for_subtree_with_bloom(self,
+                                                    ctx: &SelectCtx,
+                                                    quirks_mode: QuirksMode,
+                                                    refs: &DVec<@mut LayoutData>,
+                                                    bloom: @mut BloomFilter,
+                                                    sharing: @mut StyleSharingCache) {
+        match self.initialize_layout_data() {
+            Some(r) => refs.push(r),
+            None => {}
+        }
+
+        // At this point the filter holds `self`'s ancestors, which is what the selector
+        // engine needs when it matches `self`. Probe the sibling style-sharing cache
+        // first: on a hit we reuse a neighbour's `CompleteSelectResults` verbatim and
+        // skip matching entirely; on a miss we run the selector engine with a handler
+        // carrying the ancestor Bloom filter, store the result, and record `self` as a
+        // candidate for its later siblings.
+        if self.is_element() {
+            if !sharing.share_style_for(self) {
+                let handler = NodeSelectHandler::with_bloom(self, bloom, quirks_mode);
+                let results = ctx.select_style(&self, &handler);
+                match self.as_node().layout_data {
+                    Some(data) => data.style = Some(results),
+                    None => {}
+                }
+                sharing.add(self);
             }
+
+            bloom.insert(self.as_element().tag_name);
+        }
+        for self.each_child |kid| {
+            kid.initialize_style_for_subtree_with_bloom(ctx, quirks_mode, refs, bloom, sharing);
+        }
+        if self.is_element() {
+            bloom.remove(self.as_element().tag_name);
         }
     }
 